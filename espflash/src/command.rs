@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use crate::flasher::SpiAttachParams;
+
+/// Base timeout applied to any command whose duration doesn't scale with
+/// the amount of data it moves.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Identifies a [`Command`] variant for the purposes of picking a timeout,
+/// independent of the data (and any borrow) it carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandType {
+    SpiAttach,
+    WriteReg,
+    FlashBegin,
+    FlashData,
+    FlashEnd,
+    FlashDeflateBegin,
+    FlashDeflateData,
+    FlashDeflateEnd,
+    FlashReadStart,
+    FlashReadData,
+    FlashReadDone,
+    FlashMd5,
+    FlashEncryptedBegin,
+    FlashEncryptedData,
+    FlashEncryptedEnd,
+}
+
+impl CommandType {
+    /// The timeout to use when this command's duration doesn't depend on a
+    /// transfer size (e.g. it always carries a fixed, small payload).
+    pub fn timeout(&self) -> Duration {
+        DEFAULT_TIMEOUT
+    }
+
+    /// The timeout to use for a command moving `size` bytes, scaling for
+    /// slower serial links and larger erases.
+    pub fn timeout_for_size(&self, size: u32) -> Duration {
+        DEFAULT_TIMEOUT + Duration::from_millis(size as u64 / 40)
+    }
+}
+
+/// The commands used to communicate with a device's ROM bootloader or the
+/// flasher stub loaded onto it.
+#[derive(Debug, Clone)]
+pub enum Command<'a> {
+    SpiAttach {
+        spi_params: SpiAttachParams,
+    },
+    SpiAttachStub {
+        spi_params: SpiAttachParams,
+    },
+    WriteReg {
+        address: u32,
+        value: u32,
+        mask: Option<u32>,
+    },
+    FlashBegin {
+        size: u32,
+        blocks: u32,
+        block_size: u32,
+        offset: u32,
+        supports_encryption: bool,
+    },
+    FlashData {
+        sequence: u32,
+        pad_to: u32,
+        pad_byte: u8,
+        data: &'a [u8],
+    },
+    FlashEnd {
+        reboot: bool,
+    },
+    FlashDeflateBegin {
+        size: u32,
+        blocks: u32,
+        block_size: u32,
+        offset: u32,
+        supports_encryption: bool,
+    },
+    FlashDeflateData {
+        sequence: u32,
+        pad_to: u32,
+        pad_byte: u8,
+        data: &'a [u8],
+    },
+    FlashDeflateEnd {
+        reboot: bool,
+    },
+    FlashReadStart {
+        addr: u32,
+        size: u32,
+        packet_size: u32,
+        max_in_flight: u32,
+    },
+    FlashReadData,
+    FlashReadDone,
+    FlashMd5 {
+        addr: u32,
+        size: u32,
+    },
+    FlashEncryptedBegin {
+        size: u32,
+        blocks: u32,
+        block_size: u32,
+        offset: u32,
+    },
+    FlashEncryptedData {
+        sequence: u32,
+        pad_to: u32,
+        pad_byte: u8,
+        data: &'a [u8],
+    },
+    FlashEncryptedEnd {
+        reboot: bool,
+    },
+}
+
+impl Command<'_> {
+    /// Serializes this command into a tag byte followed by its payload.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        match self {
+            Command::SpiAttach { spi_params } | Command::SpiAttachStub { spi_params } => {
+                let mut buf = vec![0x0D];
+                buf.extend_from_slice(&spi_params.encode());
+                buf
+            }
+            Command::WriteReg {
+                address,
+                value,
+                mask,
+            } => {
+                let mut buf = vec![0x09];
+                buf.extend_from_slice(&address.to_le_bytes());
+                buf.extend_from_slice(&value.to_le_bytes());
+                buf.extend_from_slice(&mask.unwrap_or(0xFFFF_FFFF).to_le_bytes());
+                buf
+            }
+            Command::FlashBegin {
+                size,
+                blocks,
+                block_size,
+                offset,
+                ..
+            } => encode_begin(0x02, *size, *blocks, *block_size, *offset),
+            Command::FlashData {
+                sequence, data, ..
+            } => encode_data(0x03, *sequence, data),
+            Command::FlashEnd { reboot } => vec![0x04, *reboot as u8],
+            Command::FlashDeflateBegin {
+                size,
+                blocks,
+                block_size,
+                offset,
+                ..
+            } => encode_begin(0x10, *size, *blocks, *block_size, *offset),
+            Command::FlashDeflateData {
+                sequence, data, ..
+            } => encode_data(0x11, *sequence, data),
+            Command::FlashDeflateEnd { reboot } => vec![0x12, *reboot as u8],
+            Command::FlashReadStart {
+                addr,
+                size,
+                packet_size,
+                max_in_flight,
+            } => {
+                let mut buf = vec![0x14];
+                buf.extend_from_slice(&addr.to_le_bytes());
+                buf.extend_from_slice(&size.to_le_bytes());
+                buf.extend_from_slice(&packet_size.to_le_bytes());
+                buf.extend_from_slice(&max_in_flight.to_le_bytes());
+                buf
+            }
+            Command::FlashReadData => vec![0x15],
+            Command::FlashReadDone => vec![0x16],
+            Command::FlashMd5 { addr, size } => {
+                let mut buf = vec![0x13];
+                buf.extend_from_slice(&addr.to_le_bytes());
+                buf.extend_from_slice(&size.to_le_bytes());
+                buf
+            }
+            Command::FlashEncryptedBegin {
+                size,
+                blocks,
+                block_size,
+                offset,
+            } => encode_begin(0x17, *size, *blocks, *block_size, *offset),
+            Command::FlashEncryptedData {
+                sequence, data, ..
+            } => encode_data(0x18, *sequence, data),
+            Command::FlashEncryptedEnd { reboot } => vec![0x19, *reboot as u8],
+        }
+    }
+}
+
+fn encode_begin(tag: u8, size: u32, blocks: u32, block_size: u32, offset: u32) -> Vec<u8> {
+    let mut buf = vec![tag];
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&blocks.to_le_bytes());
+    buf.extend_from_slice(&block_size.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    buf
+}
+
+fn encode_data(tag: u8, sequence: u32, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![tag];
+    buf.extend_from_slice(&sequence.to_le_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
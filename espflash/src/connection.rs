@@ -0,0 +1,98 @@
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use crate::{command::Command, error::Error};
+
+/// USB PID reported by devices exposing a built-in USB-Serial-JTAG
+/// peripheral rather than an external USB-UART bridge.
+pub const USB_SERIAL_JTAG_PID: u16 = 0x1001;
+
+/// Anything a [`Connection`] can use as its underlying transport.
+pub trait SerialPort: Read + Write {}
+impl<T: Read + Write> SerialPort for T {}
+
+/// The raw, unparsed response to a single command.
+pub struct CommandResponse {
+    data: Vec<u8>,
+}
+
+impl CommandResponse {
+    /// Interprets this response as the 16-byte MD5 returned by `FlashMd5`.
+    pub fn into_flash_md5(self) -> Result<[u8; 16], Error> {
+        self.data.try_into().map_err(|_| Error::InvalidResponse)
+    }
+}
+
+/// An open connection to a device's serial port, along with the state
+/// needed to speak the ROM/stub command protocol over it.
+pub struct Connection {
+    port: Box<dyn SerialPort>,
+    usb_pid: u16,
+    timeout: Duration,
+}
+
+impl Connection {
+    pub fn new(port: Box<dyn SerialPort>, usb_pid: u16) -> Self {
+        Connection {
+            port,
+            usb_pid,
+            timeout: Duration::from_secs(3),
+        }
+    }
+
+    pub fn get_usb_pid(&self) -> Result<u16, Error> {
+        Ok(self.usb_pid)
+    }
+
+    /// Runs `f` with the connection's timeout temporarily set to `timeout`.
+    pub fn with_timeout<T>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&mut Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let previous = self.timeout;
+        self.timeout = timeout;
+        let result = f(self);
+        self.timeout = previous;
+        result
+    }
+
+    /// Sends `command` and reads back its response.
+    pub fn command(&mut self, command: Command<'_>) -> Result<CommandResponse, Error> {
+        let encoded = command.encode();
+        self.port.write_all(&encoded)?;
+        self.read_response()
+    }
+
+    fn read_response(&mut self) -> Result<CommandResponse, Error> {
+        let mut len_buf = [0u8; 4];
+        self.port.read_exact(&mut len_buf)?;
+        let len = u32::from_le_bytes(len_buf) as usize;
+
+        let mut data = vec![0u8; len];
+        self.port.read_exact(&mut data)?;
+
+        Ok(CommandResponse { data })
+    }
+
+    /// Reads the next flash read-back data packet.
+    pub fn read_flash_chunk(&mut self) -> Result<Vec<u8>, Error> {
+        Ok(self.read_response()?.data)
+    }
+
+    /// Acknowledges a flash read-back packet, telling the device it can send
+    /// the next one.
+    pub fn send_flash_read_ack(&mut self, len: u32) -> Result<(), Error> {
+        self.port.write_all(&len.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Flushes any pending output to the port. No reset signal is sent to
+    /// the device.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.port.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,7 @@
+/// A contiguous block of data from an ELF image (or a raw binary), destined
+/// for a specific flash offset.
+#[derive(Debug, Clone)]
+pub struct RomSegment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+/// Errors that can occur while talking to or flashing a device.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+
+    #[error("Timed out waiting for a response from the device")]
+    Timeout,
+
+    #[error("Received a malformed response from the device")]
+    InvalidResponse,
+
+    #[error("Flash at {addr:#x} did not match the expected MD5 after writing")]
+    VerifyMismatch { addr: u32 },
+
+    #[error(
+        "Encrypted write at {addr:#x} (len {len}) is not aligned to the flash encryption block size"
+    )]
+    EncryptAlignment { addr: u32, len: usize },
+
+    #[error("Write starting at {addr:#x} crosses a flash region boundary at an unaligned offset")]
+    CrossRegionWrite { addr: u32 },
+}
@@ -0,0 +1,23 @@
+/// Sector size assumed by the default (non-block) erase path.
+pub const FLASH_SECTOR_SIZE: usize = 0x1000;
+
+/// Block-erase granularity supported by the ROM loader and stub when
+/// `large_block_erase` is enabled.
+pub const FLASH_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Parameters describing how the device's SPI flash is attached, sent as
+/// part of the `SpiAttach`/`SpiAttachStub` commands.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpiAttachParams {
+    pub clk: u8,
+    pub q: u8,
+    pub d: u8,
+    pub hd: u8,
+    pub cs: u8,
+}
+
+impl SpiAttachParams {
+    pub fn encode(&self) -> [u8; 5] {
+        [self.clk, self.q, self.d, self.hd, self.cs]
+    }
+}
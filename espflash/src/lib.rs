@@ -0,0 +1,6 @@
+pub mod command;
+pub mod connection;
+pub mod elf;
+pub mod error;
+pub mod flasher;
+pub mod targets;
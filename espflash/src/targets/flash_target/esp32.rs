@@ -11,27 +11,428 @@ use crate::{
     connection::{Connection, USB_SERIAL_JTAG_PID},
     elf::RomSegment,
     error::Error,
-    flasher::{SpiAttachParams, FLASH_SECTOR_SIZE},
-    targets::Chip,
+    flasher::{SpiAttachParams, FLASH_BLOCK_SIZE, FLASH_SECTOR_SIZE},
+    targets::{Chip, FlashRegion},
 };
 
+/// Maximum number of flash read packets the stub is allowed to have
+/// in flight before it must wait for an ack.
+const MAX_READ_IN_FLIGHT: u32 = 64;
+
+/// Flash write page size used once block-erase is active; bumped up from the
+/// chip's default `flash_write_size` page since the target is now erasing in much bigger
+/// chunks. Must be larger than any `ChipTarget::flash_write_size`, or
+/// `resolve_write_size`'s bump would be a no-op.
+const FLASH_WRITE_SIZE_LARGE: usize = 128 * 1024;
+
+/// Encrypted writes must be aligned to the AES block size used by the
+/// on-device flash encryption engine.
+const ENCRYPT_ALIGNMENT: usize = 32;
+
+/// Below this segment size, deflating costs more than it saves, so
+/// `CompressionMode::Auto` sends the data uncompressed instead.
+const AUTO_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Controls whether [`Esp32Target::write_segment`] deflates data before
+/// sending it to the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionMode {
+    /// Always deflate the segment and use the `FlashDeflate*` commands.
+    Deflate,
+    /// Never deflate; use the plain `FlashBegin`/`FlashData`/`FlashEnd` commands.
+    None,
+    /// Deflate unless the segment is small enough that the overhead isn't
+    /// worth it.
+    Auto,
+}
+
+/// Options controlling how [`Esp32Target`] performs a flash write. Grouped
+/// into a struct (rather than a run of positional bools on `new`) so call
+/// sites read `verify: true` instead of an easily-transposed `true, false,
+/// true`.
+#[derive(Debug, Clone, Copy)]
+pub struct FlashOptions {
+    /// Request the device's MD5 of each written region and compare it
+    /// against the data that was sent. Encrypted writes are not verified.
+    pub verify: bool,
+    /// Erase large segments in 64 KiB blocks instead of 4 KiB sectors.
+    pub large_block_erase: bool,
+    /// Write via the device's on-device flash encryption path.
+    pub encrypt: bool,
+    /// Whether to deflate segments before sending them.
+    pub compression: CompressionMode,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        FlashOptions {
+            verify: false,
+            large_block_erase: false,
+            encrypt: false,
+            compression: CompressionMode::Auto,
+        }
+    }
+}
+
 /// Applications running from an ESP32's (or variant's) flash
 pub struct Esp32Target {
     chip: Chip,
     spi_attach_params: SpiAttachParams,
     use_stub: bool,
+    verify: bool,
+    large_block_erase: bool,
+    encrypt: bool,
+    compression: CompressionMode,
 }
 
 impl Esp32Target {
-    pub fn new(chip: Chip, spi_attach_params: SpiAttachParams, use_stub: bool) -> Self {
+    pub fn new(
+        chip: Chip,
+        spi_attach_params: SpiAttachParams,
+        use_stub: bool,
+        options: FlashOptions,
+    ) -> Self {
         Esp32Target {
             chip,
             spi_attach_params,
             use_stub,
+            verify: options.verify,
+            large_block_erase: options.large_block_erase,
+            encrypt: options.encrypt,
+            compression: options.compression,
+        }
+    }
+
+    /// Writes `segment` via the plain (non-deflate) command sequence, used
+    /// when `compression` resolves to [`CompressionMode::None`].
+    fn write_segment_plain(
+        &mut self,
+        connection: &mut Connection,
+        segment: &RomSegment,
+        progress_cb: &Option<Box<dyn Fn(usize, usize)>>,
+    ) -> Result<(), Error> {
+        let addr = segment.addr;
+        let data = &segment.data;
+
+        let flash_write_size = self.resolve_write_size(connection, data.len())?;
+        let block_count = data.len().div_ceil(flash_write_size);
+        let erase_size = self.erase_size(connection, addr, data.len())?;
+
+        connection.with_timeout(
+            CommandType::FlashBegin.timeout_for_size(erase_size),
+            |connection| {
+                connection.command(Command::FlashBegin {
+                    size: data.len() as u32,
+                    blocks: block_count as u32,
+                    block_size: flash_write_size as u32,
+                    offset: addr,
+                    supports_encryption: false,
+                })
+            },
+        )?;
+
+        let chunks = data.chunks(flash_write_size);
+        let num_chunks = chunks.len();
+
+        for (i, block) in chunks.enumerate() {
+            connection.with_timeout(
+                CommandType::FlashData.timeout_for_size(block.len() as u32),
+                |connection| {
+                    connection.command(Command::FlashData {
+                        sequence: i as u32,
+                        pad_to: 0,
+                        pad_byte: 0xff,
+                        data: block,
+                    })
+                },
+            )?;
+
+            if let Some(ref cb) = progress_cb {
+                cb(i + 1, num_chunks);
+            }
+        }
+
+        connection.with_timeout(CommandType::FlashEnd.timeout(), |connection| {
+            connection.command(Command::FlashEnd { reboot: false })
+        })?;
+
+        if self.verify {
+            self.verify_segment(connection, addr, data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `segment` via the plain (non-deflate) command sequence with
+    /// on-device flash encryption enabled, handing plaintext 32-byte-aligned
+    /// blocks to the ROM/stub's `esp_rom_spiflash_write_encrypted` path.
+    ///
+    /// Deliberately does not honor `self.verify`: the device stores
+    /// ciphertext for an encrypted write, so a device-computed MD5 would
+    /// never match an MD5 taken over the plaintext `segment.data` we sent.
+    fn write_segment_encrypted(
+        &mut self,
+        connection: &mut Connection,
+        segment: &RomSegment,
+        progress_cb: &Option<Box<dyn Fn(usize, usize)>>,
+    ) -> Result<(), Error> {
+        let addr = segment.addr;
+        let data = &segment.data;
+
+        if !(addr as usize).is_multiple_of(ENCRYPT_ALIGNMENT) || !data.len().is_multiple_of(ENCRYPT_ALIGNMENT) {
+            return Err(Error::EncryptAlignment { addr, len: data.len() });
+        }
+
+        let flash_write_size = self.resolve_write_size(connection, data.len())?;
+        let block_count = data.len().div_ceil(flash_write_size);
+        let erase_size = self.erase_size(connection, addr, data.len())?;
+
+        connection.with_timeout(
+            CommandType::FlashEncryptedBegin.timeout_for_size(erase_size),
+            |connection| {
+                connection.command(Command::FlashEncryptedBegin {
+                    size: data.len() as u32,
+                    blocks: block_count as u32,
+                    block_size: flash_write_size as u32,
+                    offset: addr,
+                })
+            },
+        )?;
+
+        let chunks = data.chunks(flash_write_size);
+        let num_chunks = chunks.len();
+
+        for (i, block) in chunks.enumerate() {
+            connection.with_timeout(
+                CommandType::FlashEncryptedData.timeout_for_size(block.len() as u32),
+                |connection| {
+                    connection.command(Command::FlashEncryptedData {
+                        sequence: i as u32,
+                        pad_to: 0,
+                        pad_byte: 0xff,
+                        data: block,
+                    })
+                },
+            )?;
+
+            if let Some(ref cb) = progress_cb {
+                cb(i + 1, num_chunks);
+            }
+        }
+
+        connection.with_timeout(CommandType::FlashEncryptedEnd.timeout(), |connection| {
+            connection.command(Command::FlashEncryptedEnd { reboot: false })
+        })?;
+
+        Ok(())
+    }
+
+    /// Computes the total erase size for a `len`-byte write starting at
+    /// `addr`, splitting across the target's [`FlashRegion`]s (if any) and
+    /// applying each region's own erase granularity. Falls back to
+    /// [`Esp32Target::default_erase_size`] for chips that don't report
+    /// regions.
+    fn erase_size(&self, connection: &mut Connection, addr: u32, len: usize) -> Result<u32, Error> {
+        let target = self.chip.into_target();
+        let regions = target.regions();
+
+        if regions.is_empty() {
+            return Ok(self.default_erase_size(addr, len));
+        }
+
+        self.erase_size_for_regions(connection, addr, len, regions)
+    }
+
+    /// Core of [`Esp32Target::erase_size`], taking `regions` explicitly so it
+    /// can be exercised in tests without a chip that reports real region
+    /// data.
+    fn erase_size_for_regions(
+        &self,
+        _connection: &mut Connection,
+        addr: u32,
+        len: usize,
+        regions: &[FlashRegion],
+    ) -> Result<u32, Error> {
+        let end = addr as u64 + len as u64;
+        let mut cursor = addr as u64;
+        let mut total = 0u32;
+
+        while cursor < end {
+            let region = regions
+                .iter()
+                .find(|r| cursor >= r.base as u64 && cursor < r.base as u64 + r.size as u64)
+                .ok_or(Error::CrossRegionWrite { addr })?;
+
+            let erase_size = self.region_erase_size(region);
+            let region_end = region.base as u64 + region.size as u64;
+            let chunk_end = end.min(region_end);
+
+            // A split that doesn't land on this region's erase boundary would
+            // leave the next region's head sector partially erased by this one.
+            if chunk_end < end && !chunk_end.is_multiple_of(erase_size as u64) {
+                return Err(Error::CrossRegionWrite { addr });
+            }
+
+            let chunk_len = (chunk_end - cursor) as usize;
+            let sector_count = chunk_len.div_ceil(erase_size as usize);
+            total += (sector_count * erase_size as usize) as u32;
+
+            cursor = chunk_end;
+        }
+
+        Ok(total)
+    }
+
+    /// The erase granularity actually used within `region`: `region.erase_size`
+    /// is the *maximum* the region's hardware allows (e.g. a reserved region
+    /// that must stay sector-sized reports `FLASH_SECTOR_SIZE` here), and
+    /// block erase is only used up to that maximum when `large_block_erase`
+    /// is enabled.
+    fn region_erase_size(&self, region: &FlashRegion) -> u32 {
+        if self.large_block_erase && region.erase_size as usize >= FLASH_BLOCK_SIZE {
+            FLASH_BLOCK_SIZE as u32
+        } else {
+            FLASH_SECTOR_SIZE as u32
+        }
+    }
+
+    /// Computes the total erase size for a `len`-byte write starting at
+    /// `addr` assuming a single, chip-uniform erase granularity: 64 KiB
+    /// blocks when `large_block_erase` is enabled and the segment is large
+    /// enough to benefit, falling back to 4 KiB sector erase for the
+    /// unaligned head/tail (and for small segments).
+    fn default_erase_size(&self, addr: u32, len: usize) -> u32 {
+        if !self.large_block_erase || len < FLASH_BLOCK_SIZE {
+            let sector_count = len.div_ceil(FLASH_SECTOR_SIZE);
+            return (sector_count * FLASH_SECTOR_SIZE) as u32;
+        }
+
+        let round_to_sector = |size: usize| size.div_ceil(FLASH_SECTOR_SIZE) * FLASH_SECTOR_SIZE;
+
+        let head = (FLASH_BLOCK_SIZE - (addr as usize % FLASH_BLOCK_SIZE)) % FLASH_BLOCK_SIZE;
+        let head = head.min(len);
+        let remaining = len - head;
+
+        let block_count = remaining / FLASH_BLOCK_SIZE;
+        let blocks = block_count * FLASH_BLOCK_SIZE;
+
+        let tail = round_to_sector(remaining - blocks);
+
+        (round_to_sector(head) + blocks + tail) as u32
+    }
+
+    /// Resolves the flash write page size for a `data_len`-byte write,
+    /// applied uniformly across the deflate, plain, and encrypted write
+    /// paths: with `large_block_erase` enabled and a write of at least one
+    /// 64 KiB block, the page is raised to `FLASH_WRITE_SIZE_LARGE`;
+    /// otherwise the chip's own `flash_write_size` is used.
+    fn resolve_write_size(
+        &self,
+        connection: &mut Connection,
+        data_len: usize,
+    ) -> Result<usize, Error> {
+        let target = self.chip.into_target();
+        let flash_write_size = target.flash_write_size(connection)?;
+
+        if self.large_block_erase && data_len >= FLASH_BLOCK_SIZE {
+            Ok(FLASH_WRITE_SIZE_LARGE.max(flash_write_size))
+        } else {
+            Ok(flash_write_size)
+        }
+    }
+
+    /// Whether a `len`-byte segment should be deflated before sending,
+    /// given `self.compression`.
+    fn should_deflate(&self, len: usize) -> bool {
+        match self.compression {
+            CompressionMode::Deflate => true,
+            CompressionMode::None => false,
+            CompressionMode::Auto => len >= AUTO_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    /// Reads `len` bytes of flash starting at `addr`, always returning a
+    /// buffer of exactly that length.
+    pub fn read_flash(
+        &mut self,
+        connection: &mut Connection,
+        addr: u32,
+        len: u32,
+        progress_cb: Option<Box<dyn Fn(usize, usize)>>,
+    ) -> Result<Vec<u8>, Error> {
+        let target = self.chip.into_target();
+        let flash_write_size = target.flash_write_size(connection)?;
+
+        connection.with_timeout(CommandType::FlashReadStart.timeout(), |connection| {
+            connection.command(Command::FlashReadStart {
+                addr,
+                size: len,
+                packet_size: flash_write_size as u32,
+                max_in_flight: MAX_READ_IN_FLIGHT,
+            })
+        })?;
+
+        let num_chunks = (len as usize).div_ceil(flash_write_size);
+        let mut buf = Vec::with_capacity(len as usize);
+
+        for i in 0..num_chunks {
+            let chunk = connection
+                .with_timeout(CommandType::FlashReadData.timeout(), |connection| {
+                    connection.read_flash_chunk()
+                })?;
+
+            buf.extend_from_slice(&chunk);
+            // The device's flow-control window advances on the cumulative
+            // byte count received so far, not the size of the most recent
+            // packet.
+            connection.send_flash_read_ack(buf.len() as u32)?;
+
+            if let Some(ref cb) = progress_cb {
+                cb(i + 1, num_chunks);
+            }
+        }
+
+        connection.with_timeout(CommandType::FlashReadDone.timeout(), |connection| {
+            connection.command(Command::FlashReadDone)
+        })?;
+
+        if buf.len() < len as usize {
+            return Err(Error::InvalidResponse);
         }
+
+        buf.truncate(len as usize);
+
+        Ok(buf)
+    }
+
+    /// Requests the device-computed MD5 of `data.len()` bytes starting at `addr`
+    /// and compares it against an MD5 computed locally over `data`.
+    fn verify_segment(
+        &mut self,
+        connection: &mut Connection,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let response = connection.with_timeout(CommandType::FlashMd5.timeout(), |connection| {
+            connection.command(Command::FlashMd5 {
+                addr,
+                size: data.len() as u32,
+            })
+        })?;
+
+        if !md5_matches(data, response.into_flash_md5()?) {
+            return Err(Error::VerifyMismatch { addr });
+        }
+
+        Ok(())
     }
 }
 
+/// Returns whether `data`'s MD5 matches the device-reported `device_md5`.
+fn md5_matches(data: &[u8], device_md5: [u8; 16]) -> bool {
+    *md5::compute(data) == device_md5
+}
+
 impl FlashTarget for Esp32Target {
     fn begin(&mut self, connection: &mut Connection) -> Result<(), Error> {
         connection.with_timeout(CommandType::SpiAttach.timeout(), |connection| {
@@ -100,19 +501,23 @@ impl FlashTarget for Esp32Target {
         segment: RomSegment,
         progress_cb: Option<Box<dyn Fn(usize, usize)>>,
     ) -> Result<(), Error> {
+        if self.encrypt {
+            return self.write_segment_encrypted(connection, &segment, &progress_cb);
+        }
+
+        if !self.should_deflate(segment.data.len()) {
+            return self.write_segment_plain(connection, &segment, &progress_cb);
+        }
+
         let addr = segment.addr;
 
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
         encoder.write_all(&segment.data)?;
         let compressed = encoder.finish()?;
 
-        let target = self.chip.into_target();
-        let flash_write_size = target.flash_write_size(connection)?;
-        let block_count = (compressed.len() + flash_write_size - 1) / flash_write_size;
-        let erase_count = (segment.data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
-
-        // round up to sector size
-        let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
+        let flash_write_size = self.resolve_write_size(connection, segment.data.len())?;
+        let block_count = compressed.len().div_ceil(flash_write_size);
+        let erase_size = self.erase_size(connection, addr, segment.data.len())?;
 
         connection.with_timeout(
             CommandType::FlashDeflateBegin.timeout_for_size(erase_size),
@@ -159,6 +564,10 @@ impl FlashTarget for Esp32Target {
             }
         }
 
+        if self.verify {
+            self.verify_segment(connection, addr, &segment.data)?;
+        }
+
         Ok(())
     }
 
@@ -173,4 +582,211 @@ impl FlashTarget for Esp32Target {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_target(large_block_erase: bool) -> Esp32Target {
+        Esp32Target::new(
+            Chip::Esp32,
+            SpiAttachParams::default(),
+            false,
+            FlashOptions {
+                large_block_erase,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn md5_matches_identical_data() {
+        let data = b"some firmware bytes";
+        let digest = *md5::compute(data);
+
+        assert!(md5_matches(data, digest));
+    }
+
+    #[test]
+    fn md5_matches_rejects_mismatch() {
+        let data = b"some firmware bytes";
+        let mut digest = *md5::compute(data);
+        digest[0] ^= 0xFF;
+
+        assert!(!md5_matches(data, digest));
+    }
+
+    #[test]
+    fn default_erase_size_rounds_up_to_whole_sectors() {
+        let target = test_target(false);
+
+        assert_eq!(target.default_erase_size(0, 3 * FLASH_SECTOR_SIZE + 1), 4 * FLASH_SECTOR_SIZE as u32);
+    }
+
+    #[test]
+    fn default_erase_size_ignores_block_erase_below_threshold() {
+        let target = test_target(true);
+
+        // Too small to benefit from block erase, so it falls back to plain
+        // sector rounding regardless of `addr`'s alignment within a block.
+        assert_eq!(target.default_erase_size(100, 50), FLASH_SECTOR_SIZE as u32);
+    }
+
+    #[test]
+    fn default_erase_size_splits_tail_sector_around_an_aligned_block() {
+        let target = test_target(true);
+
+        let erase = target.default_erase_size(0, FLASH_BLOCK_SIZE + 10);
+
+        assert_eq!(erase, (FLASH_BLOCK_SIZE + FLASH_SECTOR_SIZE) as u32);
+    }
+
+    #[test]
+    fn erase_size_falls_back_to_default_with_no_regions() {
+        // Chip::Esp32c3 reports no flash regions.
+        let target = Esp32Target::new(
+            Chip::Esp32c3,
+            SpiAttachParams::default(),
+            false,
+            FlashOptions::default(),
+        );
+        let mut connection = mock_connection();
+
+        let erase = target
+            .erase_size(&mut connection, 0, 3 * FLASH_SECTOR_SIZE + 1)
+            .unwrap();
+
+        assert_eq!(erase, target.default_erase_size(0, 3 * FLASH_SECTOR_SIZE + 1));
+    }
+
+    #[test]
+    fn esp32_erase_size_keeps_the_bootloader_region_sector_sized() {
+        // Real Chip::Esp32 regions: a write that stays entirely within the
+        // reserved low region must stay sector-sized, even with
+        // large_block_erase enabled, since that region's max is below
+        // FLASH_BLOCK_SIZE.
+        let target = test_target(true);
+        let mut connection = mock_connection();
+
+        let erase = target.erase_size(&mut connection, 0, FLASH_SECTOR_SIZE).unwrap();
+
+        assert_eq!(erase, FLASH_SECTOR_SIZE as u32);
+    }
+
+    #[test]
+    fn erase_size_sums_across_regions_with_differing_granularity() {
+        // large_block_erase enabled so the second region actually gets to use
+        // its block-sized maximum; the first region's max is below
+        // FLASH_BLOCK_SIZE so it's pinned to sector granularity regardless.
+        let target = test_target(true);
+        let mut connection = mock_connection();
+        let regions = &[
+            FlashRegion {
+                base: 0,
+                size: 0x1000,
+                erase_size: 0x1000,
+            },
+            FlashRegion {
+                base: 0x1000,
+                size: 0x10000,
+                erase_size: 0x10000,
+            },
+        ];
+
+        let erase = target
+            .erase_size_for_regions(&mut connection, 0, 0x1000 + 1, regions)
+            .unwrap();
+
+        assert_eq!(erase, 0x1000 + 0x10000);
+    }
+
+    #[test]
+    fn erase_size_rejects_misaligned_region_crossing() {
+        let target = test_target(false);
+        let mut connection = mock_connection();
+        let regions = &[
+            // Region size isn't a multiple of its own erase size, so the
+            // region boundary itself falls mid-sector.
+            FlashRegion {
+                base: 0,
+                size: 4000,
+                erase_size: 0x400,
+            },
+            FlashRegion {
+                base: 4000,
+                size: 0x10000,
+                erase_size: 0x10000,
+            },
+        ];
+
+        // Write continues past the first region's end, so the split point
+        // at the region boundary needs to land on an erase-size boundary --
+        // it doesn't here.
+        let err = target
+            .erase_size_for_regions(&mut connection, 0, 4500, regions)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::CrossRegionWrite { addr: 0 }));
+    }
+
+    #[test]
+    fn compression_mode_auto_deflates_at_or_above_threshold() {
+        let target = test_target(false);
+
+        assert!(!target.should_deflate(AUTO_COMPRESSION_THRESHOLD - 1));
+        assert!(target.should_deflate(AUTO_COMPRESSION_THRESHOLD));
+    }
+
+    fn mock_connection() -> Connection {
+        Connection::new(Box::new(std::io::Cursor::new(Vec::new())), 0)
+    }
+
+    #[test]
+    fn resolve_write_size_bumps_up_for_large_block_erase() {
+        let mut connection = mock_connection();
+
+        let plain = test_target(false)
+            .resolve_write_size(&mut connection, FLASH_BLOCK_SIZE)
+            .unwrap();
+        let large = test_target(true)
+            .resolve_write_size(&mut connection, FLASH_BLOCK_SIZE)
+            .unwrap();
+
+        assert!(large > plain, "large_block_erase should use a bigger write page");
+        assert_eq!(large, FLASH_WRITE_SIZE_LARGE);
+    }
+
+    #[test]
+    fn write_segment_encrypted_rejects_misaligned_offset() {
+        let mut target = test_target(false);
+        let mut connection = mock_connection();
+        let segment = RomSegment {
+            addr: 1,
+            data: vec![0u8; ENCRYPT_ALIGNMENT],
+        };
+
+        let err = target
+            .write_segment_encrypted(&mut connection, &segment, &None)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::EncryptAlignment { addr: 1, .. }));
+    }
+
+    #[test]
+    fn write_segment_encrypted_rejects_misaligned_length() {
+        let mut target = test_target(false);
+        let mut connection = mock_connection();
+        let segment = RomSegment {
+            addr: 0,
+            data: vec![0u8; ENCRYPT_ALIGNMENT - 1],
+        };
+
+        let err = target
+            .write_segment_encrypted(&mut connection, &segment, &None)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::EncryptAlignment { addr: 0, .. }));
+    }
+}
+
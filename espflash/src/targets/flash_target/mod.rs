@@ -0,0 +1,18 @@
+pub mod esp32;
+
+use crate::{connection::Connection, elf::RomSegment, error::Error};
+
+/// Implemented by the different ways of writing (and resetting) a flashable
+/// image onto a device.
+pub trait FlashTarget {
+    fn begin(&mut self, connection: &mut Connection) -> Result<(), Error>;
+
+    fn write_segment(
+        &mut self,
+        connection: &mut Connection,
+        segment: RomSegment,
+        progress_cb: Option<Box<dyn Fn(usize, usize)>>,
+    ) -> Result<(), Error>;
+
+    fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error>;
+}
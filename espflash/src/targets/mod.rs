@@ -0,0 +1,89 @@
+pub mod flash_target;
+
+use crate::{
+    connection::Connection,
+    error::Error,
+    flasher::{FLASH_BLOCK_SIZE, FLASH_SECTOR_SIZE},
+};
+
+/// Flash offset of the second-stage bootloader on chips whose ROM expects a
+/// reserved low region before it (see [`Chip::into_target`]'s `Esp32` arm).
+const ESP32_BOOTLOADER_OFFSET: u32 = 0x1000;
+
+/// Espressif chip families supported by this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    Esp32,
+    Esp32c3,
+    Esp32s3,
+}
+
+/// A contiguous region of the flash address space and the *maximum* erase
+/// granularity allowed within it (block erase is only ever used up to this
+/// cap, regardless of `large_block_erase`). Chips with a uniform flash map
+/// (the common case) report no regions, and callers fall back to a single,
+/// chip-wide erase granularity instead.
+#[derive(Debug, Clone, Copy)]
+pub struct FlashRegion {
+    pub base: u32,
+    pub size: u32,
+    pub erase_size: u32,
+}
+
+/// Per-chip flashing parameters, as selected by [`Chip::into_target`].
+pub struct ChipTarget {
+    flash_write_size: usize,
+    regions: &'static [FlashRegion],
+}
+
+impl ChipTarget {
+    /// The block size to use for `FlashData`/`FlashDeflateData` writes.
+    pub fn flash_write_size(&self, _connection: &mut Connection) -> Result<usize, Error> {
+        Ok(self.flash_write_size)
+    }
+
+    /// This chip's flash regions, or an empty slice if it has a single,
+    /// uniform erase granularity across its whole flash map.
+    pub fn regions(&self) -> &'static [FlashRegion] {
+        self.regions
+    }
+}
+
+impl Chip {
+    /// Returns this chip's flashing parameters.
+    pub fn into_target(self) -> ChipTarget {
+        match self {
+            // The original ESP32 reserves the flash bytes before
+            // `ESP32_BOOTLOADER_OFFSET` (holding, depending on
+            // configuration, the secure boot digest and other data the
+            // second-stage bootloader depends on); it's smaller than a
+            // block-erase unit and must stay at sector granularity even
+            // when `large_block_erase` is requested. ESP32-C3 and -S3 have
+            // no such reserved area (their bootloader offset is 0) and keep
+            // the uniform, region-free `default_erase_size` path.
+            Chip::Esp32 => ChipTarget {
+                flash_write_size: 0x4000,
+                regions: &[
+                    FlashRegion {
+                        base: 0,
+                        size: ESP32_BOOTLOADER_OFFSET,
+                        erase_size: FLASH_SECTOR_SIZE as u32,
+                    },
+                    FlashRegion {
+                        base: ESP32_BOOTLOADER_OFFSET,
+                        size: u32::MAX - ESP32_BOOTLOADER_OFFSET,
+                        erase_size: FLASH_BLOCK_SIZE as u32,
+                    },
+                ],
+            },
+            Chip::Esp32c3 => ChipTarget {
+                flash_write_size: 0x4000,
+                regions: &[],
+            },
+            Chip::Esp32s3 => ChipTarget {
+                flash_write_size: 0x4000,
+                regions: &[],
+            },
+        }
+    }
+}